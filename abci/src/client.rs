@@ -0,0 +1,70 @@
+//! ABCI client, generic over the connection's byte stream so plaintext TCP and TLS transports
+//! share all request-dispatch code.
+
+use std::sync::Arc;
+
+use async_std::net::{TcpStream, ToSocketAddrs};
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use tendermint::abci::{request, response, Request, Response};
+
+use crate::codec;
+use crate::error::Error;
+
+/// An ABCI client: sends requests to a running [`Server`](crate::Server) application and awaits
+/// its responses.
+#[async_trait]
+pub trait Client {
+    /// Send a request and wait for the matching response.
+    async fn perform(&mut self, request: Request) -> Result<Response, Error>;
+
+    async fn echo(&mut self, req: request::Echo) -> Result<response::Echo, Error> {
+        match self.perform(Request::Echo(req)).await? {
+            Response::Echo(res) => Ok(res),
+            _ => Err(Error::unexpected_response()),
+        }
+    }
+}
+
+/// An ABCI client built on `async-std`, generic over the connection's byte stream.
+pub struct AsyncStdClient<S> {
+    stream: S,
+}
+
+impl AsyncStdClient<TcpStream> {
+    /// Connect to an ABCI server over plaintext TCP.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+        let stream = TcpStream::connect(addr).await.map_err(Error::io)?;
+        Ok(Self { stream })
+    }
+}
+
+impl AsyncStdClient<async_tls::client::TlsStream<TcpStream>> {
+    /// Connect to an ABCI server over TLS, presenting a client certificate when `config` is
+    /// configured for mutual authentication.
+    ///
+    /// `host` is used both to resolve the TCP connection and as the SNI/certificate name the
+    /// handshake validates the server against, the same way `Session::new_quic` in
+    /// `src/session.rs` keeps the name it dials separate from a hardcoded one.
+    pub async fn connect_tls(
+        host: &str,
+        port: u16,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Self, Error> {
+        let tcp = TcpStream::connect((host, port)).await.map_err(Error::io)?;
+        let connector: async_tls::TlsConnector = config.into();
+        let stream = connector.connect(host, tcp).await.map_err(Error::io)?;
+        Ok(Self { stream })
+    }
+}
+
+#[async_trait]
+impl<S> Client for AsyncStdClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn perform(&mut self, request: Request) -> Result<Response, Error> {
+        codec::write_message(&mut self.stream, &request).await?;
+        codec::read_message(&mut self.stream).await
+    }
+}
@@ -0,0 +1,16 @@
+//! A trivial [`Server`] application that echoes back whatever it's asked, used in integration
+//! tests.
+
+use crate::Server;
+
+/// An ABCI application that simply echoes back what it's given.
+#[derive(Clone, Default)]
+pub struct EchoApp;
+
+impl EchoApp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Server for EchoApp {}
@@ -0,0 +1,52 @@
+//! Errors produced by the ABCI client/server.
+
+use std::fmt;
+
+/// An error encountered while driving an ABCI client or server.
+#[derive(Debug)]
+pub struct Error {
+    kind: Kind,
+}
+
+#[derive(Debug)]
+enum Kind {
+    Io(std::io::Error),
+    Encode(prost::EncodeError),
+    Decode(String),
+    UnexpectedResponse,
+}
+
+impl Error {
+    pub(crate) fn io(err: std::io::Error) -> Self {
+        Self { kind: Kind::Io(err) }
+    }
+
+    pub(crate) fn encode(err: prost::EncodeError) -> Self {
+        Self { kind: Kind::Encode(err) }
+    }
+
+    pub(crate) fn decode(msg: impl Into<String>) -> Self {
+        Self {
+            kind: Kind::Decode(msg.into()),
+        }
+    }
+
+    pub(crate) fn unexpected_response() -> Self {
+        Self {
+            kind: Kind::UnexpectedResponse,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            Kind::Io(e) => write!(f, "I/O error: {}", e),
+            Kind::Encode(e) => write!(f, "failed to encode ABCI message: {}", e),
+            Kind::Decode(msg) => write!(f, "failed to decode ABCI message: {}", msg),
+            Kind::UnexpectedResponse => write!(f, "unexpected ABCI response variant"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
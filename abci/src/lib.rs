@@ -0,0 +1,27 @@
+//! ABCI client/server built on `async-std`.
+//!
+//! Transports are generic over the connection's byte stream (see [`codec`]), so plaintext TCP
+//! and TLS share all request-dispatch code: only binding/connecting differs.
+
+mod codec;
+mod error;
+
+#[cfg(feature = "echo-app")]
+mod echo;
+
+#[cfg(feature = "with-async-std")]
+mod server;
+
+#[cfg(all(feature = "with-async-std", feature = "client"))]
+mod client;
+
+pub use error::Error;
+
+#[cfg(feature = "echo-app")]
+pub use echo::EchoApp;
+
+#[cfg(feature = "with-async-std")]
+pub use server::{AsyncStdServer, PlainAcceptor, Server, TlsAcceptor};
+
+#[cfg(all(feature = "with-async-std", feature = "client"))]
+pub use client::{AsyncStdClient, Client};
@@ -0,0 +1,173 @@
+//! ABCI server, generic over the accepted connection's byte stream so plaintext TCP and TLS
+//! transports share all request-dispatch code.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::task;
+use futures::future::{select, Either};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::StreamExt;
+use tendermint::abci::{request, response, Request, Response};
+
+use crate::codec;
+use crate::error::Error;
+
+/// An ABCI application, driven by a server over whatever transport it's bound to.
+///
+/// Implementations are synchronous on purpose: the server dispatches each connection on its own
+/// task, so a slow handler only blocks the peer that issued the request.
+pub trait Server: Clone + Send + 'static {
+    fn echo(&self, request: request::Echo) -> response::Echo {
+        response::Echo {
+            message: request.message,
+        }
+    }
+}
+
+fn dispatch<App: Server>(app: &App, request: Request) -> Response {
+    match request {
+        Request::Echo(req) => Response::Echo(app.echo(req)),
+    }
+}
+
+/// Read requests off `stream` and write back `app`'s responses until the peer disconnects or a
+/// request can't be read or decoded.
+async fn serve_connection<S, App>(mut stream: S, app: App) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+    App: Server,
+{
+    loop {
+        let request: Request = match codec::read_message(&mut stream).await {
+            Ok(request) => request,
+            // peer closed the connection, or sent a message that couldn't be read or decoded
+            Err(_) => return Ok(()),
+        };
+        let response = dispatch(&app, request);
+        codec::write_message(&mut stream, &response).await?;
+    }
+}
+
+/// Accepts a raw `TcpStream` as-is.
+pub struct PlainAcceptor;
+
+/// Wraps each accepted `TcpStream` in a TLS session, requiring and verifying a client
+/// certificate when the underlying `rustls::ServerConfig` was built with one.
+pub struct TlsAcceptor(async_tls::TlsAcceptor);
+
+/// An ABCI server built on `async-std`, generic over how an accepted `TcpStream` is turned into
+/// the byte stream [`serve_connection`] dispatches requests over.
+pub struct AsyncStdServer<App, Acceptor = PlainAcceptor> {
+    app: App,
+    listener: TcpListener,
+    acceptor: Acceptor,
+    term_rx: Receiver<()>,
+}
+
+impl<App, Acceptor> AsyncStdServer<App, Acceptor> {
+    /// The address this server ended up bound to (useful when binding to port `0`).
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener
+            .local_addr()
+            .expect("bound listener has a local address")
+    }
+}
+
+impl<App: Server> AsyncStdServer<App, PlainAcceptor> {
+    /// Bind a plaintext ABCI server to `addr`.
+    pub async fn bind(
+        addr: impl async_std::net::ToSocketAddrs,
+        app: App,
+    ) -> Result<(Self, Sender<()>), Error> {
+        let listener = TcpListener::bind(addr).await.map_err(Error::io)?;
+        let (term_tx, term_rx) = channel::bounded(1);
+        Ok((
+            Self {
+                app,
+                listener,
+                acceptor: PlainAcceptor,
+                term_rx,
+            },
+            term_tx,
+        ))
+    }
+
+    /// Accept connections and serve them until a message arrives on the channel handed back by
+    /// [`Self::bind`].
+    pub async fn listen(self) -> Result<(), Error> {
+        let Self {
+            app,
+            listener,
+            acceptor: _,
+            mut term_rx,
+        } = self;
+
+        let mut incoming = listener.incoming();
+        loop {
+            match select(incoming.next(), term_rx.recv()).await {
+                Either::Left((Some(stream), _)) => {
+                    let stream = stream.map_err(Error::io)?;
+                    let app = app.clone();
+                    task::spawn(async move {
+                        let _ = serve_connection(stream, app).await;
+                    });
+                }
+                Either::Left((None, _)) | Either::Right(_) => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<App: Server> AsyncStdServer<App, TlsAcceptor> {
+    /// Bind a TLS-secured ABCI server to `addr`. When `server_config` is configured with a
+    /// client certificate verifier, a connecting client without a valid certificate is rejected
+    /// during the handshake, before any ABCI request ever reaches `app`.
+    pub async fn bind_tls(
+        addr: impl async_std::net::ToSocketAddrs,
+        app: App,
+        server_config: Arc<rustls::ServerConfig>,
+    ) -> Result<(Self, Sender<()>), Error> {
+        let listener = TcpListener::bind(addr).await.map_err(Error::io)?;
+        let (term_tx, term_rx) = channel::bounded(1);
+        Ok((
+            Self {
+                app,
+                listener,
+                acceptor: TlsAcceptor(server_config.into()),
+                term_rx,
+            },
+            term_tx,
+        ))
+    }
+
+    /// Accept connections, upgrade each to TLS, and serve them until a message arrives on the
+    /// channel handed back by [`Self::bind_tls`].
+    pub async fn listen(self) -> Result<(), Error> {
+        let Self {
+            app,
+            listener,
+            acceptor,
+            mut term_rx,
+        } = self;
+
+        let mut incoming = listener.incoming();
+        loop {
+            match select(incoming.next(), term_rx.recv()).await {
+                Either::Left((Some(stream), _)) => {
+                    let stream: TcpStream = stream.map_err(Error::io)?;
+                    let app = app.clone();
+                    let acceptor = acceptor.0.clone();
+                    task::spawn(async move {
+                        if let Ok(tls_stream) = acceptor.accept(stream).await {
+                            let _ = serve_connection(tls_stream, app).await;
+                        }
+                    });
+                }
+                Either::Left((None, _)) | Either::Right(_) => return Ok(()),
+            }
+        }
+    }
+}
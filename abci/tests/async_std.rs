@@ -22,4 +22,70 @@ mod async_std_integration {
         term_tx.send(()).await.unwrap();
         server_handle.await.unwrap();
     }
+
+    #[cfg(feature = "tls")]
+    #[async_std::test]
+    async fn echo_tls() {
+        let (server_config, client_config) = tls_test_configs();
+
+        let app = EchoApp::new();
+        let (server, term_tx) = AsyncStdServer::bind_tls("127.0.0.1:0", app, server_config)
+            .await
+            .unwrap();
+        let server_addr = server.local_addr();
+        let server_handle = async_std::task::spawn(async move { server.listen().await });
+
+        let mut client = AsyncStdClient::connect_tls("localhost", server_addr.port(), client_config)
+            .await
+            .unwrap();
+        let res = client
+            .echo(Echo::new("Hello ABCI over TLS!".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(res.message, "Hello ABCI over TLS!");
+
+        term_tx.send(()).await.unwrap();
+        server_handle.await.unwrap();
+    }
+
+    /// Self-signed server and client certs plus matching `rustls` configs, with mutual auth
+    /// enabled so the server requires and verifies a client certificate too.
+    #[cfg(feature = "tls")]
+    fn tls_test_configs() -> (std::sync::Arc<rustls::ServerConfig>, std::sync::Arc<rustls::ClientConfig>) {
+        use std::sync::Arc;
+
+        let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let client_cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+
+        let mut client_roots = rustls::RootCertStore::empty();
+        client_roots
+            .add(&rustls::Certificate(server_cert.serialize_der().unwrap()))
+            .unwrap();
+        let mut server_roots = rustls::RootCertStore::empty();
+        server_roots
+            .add(&rustls::Certificate(client_cert.serialize_der().unwrap()))
+            .unwrap();
+
+        let server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(Arc::new(
+                rustls::server::AllowAnyAuthenticatedClient::new(server_roots),
+            ))
+            .with_single_cert(
+                vec![rustls::Certificate(server_cert.serialize_der().unwrap())],
+                rustls::PrivateKey(server_cert.serialize_private_key_der()),
+            )
+            .unwrap();
+
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(client_roots)
+            .with_client_auth_cert(
+                vec![rustls::Certificate(client_cert.serialize_der().unwrap())],
+                rustls::PrivateKey(client_cert.serialize_private_key_der()),
+            )
+            .unwrap();
+
+        (Arc::new(server_config), Arc::new(client_config))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,143 @@
+//! Peer connection supervision: the connection state machine ([`Protocol`]) together with the
+//! inputs it consumes and the outputs it produces, i.e. the side effects (dialing, sending
+//! messages, stopping connections) to carry out and the events the rest of the node reacts to.
+
+mod protocol;
+
+pub use protocol::Protocol;
+
+use std::net::SocketAddr;
+
+use eyre::Report;
+
+use tendermint::node;
+
+use crate::message;
+
+/// Which side initiated a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Direction {
+    /// We accepted an inbound connection.
+    Incoming,
+    /// We dialed the peer.
+    Outgoing,
+}
+
+impl Direction {
+    /// The direction the peer would see for the same physical connection.
+    pub fn opposite(self) -> Self {
+        match self {
+            Self::Incoming => Self::Outgoing,
+            Self::Outgoing => Self::Incoming,
+        }
+    }
+}
+
+/// Identifies one physical connection attempt, assigned by whatever accepts or dials it.
+///
+/// `node::Id` alone can't tell two live sockets to the same peer apart, which is exactly the
+/// situation a simultaneous-open clash or a same-direction duplicate connection puts us in
+/// (briefly, two sockets for one `node::Id`). Every input or effect that needs to name a
+/// specific socket rather than "whatever we currently have for this peer" carries a `ConnId`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ConnId(pub u64);
+
+/// Everything needed to dial a peer.
+#[derive(Clone, Debug)]
+pub struct ConnectInfo {
+    pub id: node::Id,
+    pub addr: SocketAddr,
+}
+
+/// Commands issued by the application to the supervisor.
+#[derive(Debug)]
+pub enum Command {
+    /// Start accepting inbound connections.
+    Accept,
+    /// Dial the given peer.
+    Connect(ConnectInfo),
+    /// Tear down the connection to a peer.
+    Disconnect(node::Id),
+    /// Send a message to an upgraded peer.
+    Msg(node::Id, message::Send),
+}
+
+/// Inputs driving [`Protocol::transition`].
+#[derive(Debug)]
+pub enum Input {
+    /// An inbound connection has been accepted.
+    Accepted(ConnId, node::Id),
+    /// A command from the application.
+    Command(Command),
+    /// An outbound connection has completed.
+    Connected(ConnId, node::Id),
+    /// The peer rejected this connection as a duplicate, which means it already sees the
+    /// opposite direction as connected too.
+    DuplicateConnRejected(ConnId, node::Id, Report),
+    /// A message has arrived from an upgraded peer.
+    Receive(node::Id, message::Receive),
+    /// The connection identified by `ConnId` has stopped.
+    Stopped(ConnId, node::Id, Option<Report>),
+    /// The peer's nonce for an in-flight simultaneous-open tie-break has arrived over the
+    /// connection identified by `ConnId`.
+    Tiebreak(ConnId, node::Id, [u8; 32]),
+    /// The connection identified by `ConnId` has completed the upgrade handshake.
+    Upgraded(ConnId, node::Id),
+    /// The upgrade handshake for the connection identified by `ConnId` has failed.
+    UpgradeFailed(ConnId, node::Id, Report),
+}
+
+/// Side effects the supervisor must carry out.
+#[derive(Debug)]
+pub enum Internal {
+    /// Start listening for inbound connections.
+    Accept,
+    /// Dial the given peer.
+    Connect(ConnectInfo),
+    /// Send a message to a peer.
+    SendMessage(node::Id, message::Send),
+    /// Stop the connection identified by `ConnId`.
+    Stop(ConnId, node::Id),
+    /// Send our nonce for a simultaneous-open tie-break over the connection identified by
+    /// `ConnId`.
+    Tiebreak(ConnId, node::Id, [u8; 32]),
+    /// Run the post-connect upgrade handshake for the connection identified by `ConnId`.
+    Upgrade(ConnId, node::Id),
+}
+
+/// Events surfaced to the application.
+#[derive(Debug)]
+pub enum Event {
+    /// A connection to a peer has been established (but not yet upgraded).
+    Connected(node::Id, Direction),
+    /// A connection to a peer has ended.
+    Disconnected(node::Id, Report),
+    /// A message has arrived from a peer.
+    Message(node::Id, message::Receive),
+    /// A simultaneous-open clash for a peer has been settled; this is the direction we kept.
+    RoleResolved(node::Id, Direction),
+    /// A connection has completed the upgrade handshake.
+    Upgraded(node::Id),
+    /// The upgrade handshake for a connection has failed.
+    UpgradeFailed(node::Id, Report),
+}
+
+/// Output of a [`Protocol`] transition: either an event for the application or an internal side
+/// effect for the supervisor to carry out.
+#[derive(Debug)]
+pub enum Output {
+    Event(Event),
+    Internal(Internal),
+}
+
+impl From<Event> for Output {
+    fn from(event: Event) -> Self {
+        Self::Event(event)
+    }
+}
+
+impl From<Internal> for Output {
+    fn from(internal: Internal) -> Self {
+        Self::Internal(internal)
+    }
+}
@@ -1,67 +1,204 @@
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 
 use eyre::Report;
+use rand::RngCore;
 
 use tendermint::node;
 
 use crate::message;
 
-use super::{Command, Direction, Event, Input, Internal, Output};
+use super::{Command, ConnId, Direction, Event, Input, Internal, Output};
+
+/// Random value exchanged by both ends of a simultaneous-open clash. The side with the
+/// numerically larger nonce becomes the dialer; a tie means neither side can be trusted to
+/// break the clash and both connections are dropped.
+type Nonce = [u8; 32];
+
+/// State kept for a `node::Id` while the two sides are deciding who dials and who responds.
+///
+/// `existing` and `incoming` each pin down both the connection and the direction it was opened
+/// in, so resolution can stop the loser's exact socket instead of guessing which of the two
+/// live connections for this `node::Id` it meant.
+struct TieBreak {
+    /// The connection we already had for this peer before the clash.
+    existing: (ConnId, Direction),
+    /// The connection that clashed with it.
+    incoming: (ConnId, Direction),
+    /// Nonce we generated and announced to the peer over `incoming`.
+    local_nonce: Nonce,
+}
 
 #[derive(Default)]
 pub struct Protocol {
-    connected: HashMap<node::Id, Direction>,
+    connected: HashMap<node::Id, (ConnId, Direction)>,
     stopped: HashSet<node::Id>,
-    upgraded: HashSet<node::Id>,
+    upgraded: HashMap<node::Id, ConnId>,
+    tiebreaks: HashMap<node::Id, TieBreak>,
 }
 
 impl Protocol {
     pub fn transition(&mut self, input: Input) -> Vec<Output> {
         match input {
-            Input::Accepted(id) => self.handle_accepted(id),
+            Input::Accepted(conn_id, id) => self.handle_accepted(conn_id, id),
             Input::Command(command) => self.handle_command(command),
-            Input::Connected(id) => self.handle_connected(id),
-            Input::DuplicateConnRejected(_id, _report) => todo!(),
+            Input::Connected(conn_id, id) => self.handle_connected(conn_id, id),
+            Input::DuplicateConnRejected(conn_id, id, report) => {
+                self.handle_duplicate_conn_rejected(conn_id, id, report)
+            }
             Input::Receive(id, msg) => self.handle_receive(id, msg),
-            Input::Stopped(id, report) => self.handle_stopped(id, report),
-            Input::Upgraded(id) => self.handle_upgraded(id),
-            Input::UpgradeFailed(id, err) => self.handle_upgrade_failed(id, err),
+            Input::Stopped(conn_id, id, report) => self.handle_stopped(conn_id, id, report),
+            Input::Tiebreak(conn_id, id, nonce) => self.handle_tiebreak(conn_id, id, nonce),
+            Input::Upgraded(conn_id, id) => self.handle_upgraded(conn_id, id),
+            Input::UpgradeFailed(conn_id, id, err) => self.handle_upgrade_failed(conn_id, id, err),
         }
     }
 
-    fn handle_accepted(&mut self, id: node::Id) -> Vec<Output> {
-        // TODO(xla): Ensure we only allow one connection per node. Unless a higher-level protocol
-        // like PEX is taking care of it.
-        self.connected.insert(id, Direction::Incoming);
-
-        vec![
-            Output::from(Event::Connected(id, Direction::Incoming)),
-            Output::from(Internal::Upgrade(id)),
-        ]
+    fn handle_accepted(&mut self, conn_id: ConnId, id: node::Id) -> Vec<Output> {
+        self.open(conn_id, id, Direction::Incoming)
     }
 
     fn handle_command(&mut self, command: Command) -> Vec<Output> {
         match command {
             Command::Accept => vec![Output::from(Internal::Accept)],
             Command::Connect(info) => vec![Output::from(Internal::Connect(info))],
-            Command::Disconnect(id) => {
-                vec![Output::Internal(Internal::Stop(id))]
-            }
+            Command::Disconnect(id) => match self.connected.get(&id) {
+                Some((conn_id, _)) => vec![Output::from(Internal::Stop(*conn_id, id))],
+                None => vec![],
+            },
             Command::Msg(peer_id, msg) => match self.upgraded.get(&peer_id) {
-                Some(peer_id) => vec![Output::from(Internal::SendMessage(*peer_id, msg))],
+                Some(_) => vec![Output::from(Internal::SendMessage(peer_id, msg))],
                 None => vec![],
             },
         }
     }
 
-    fn handle_connected(&mut self, id: node::Id) -> Vec<Output> {
-        // TODO(xla): Ensure we only allow one connection per node. Unless a higher-level protocol
-        // like PEX is taking care of it.
-        self.connected.insert(id, Direction::Outgoing);
+    fn handle_connected(&mut self, conn_id: ConnId, id: node::Id) -> Vec<Output> {
+        self.open(conn_id, id, Direction::Outgoing)
+    }
+
+    /// Record a newly established connection, kick off simultaneous-open resolution if one in
+    /// the opposite direction is already known for this `node::Id`, or stop a redundant
+    /// duplicate arriving in the same direction as one we already have.
+    fn open(&mut self, conn_id: ConnId, id: node::Id, direction: Direction) -> Vec<Output> {
+        match self.connected.get(&id) {
+            Some((existing_conn, existing_dir)) if *existing_dir != direction => {
+                let existing = (*existing_conn, *existing_dir);
+                self.start_tiebreak(id, existing, (conn_id, direction))
+            }
+            // A second connection in the same direction we already have is just a redundant
+            // duplicate: stop the new one, leave the established connection alone.
+            Some(_) => vec![Output::from(Internal::Stop(conn_id, id))],
+            None => {
+                self.connected.insert(id, (conn_id, direction));
+
+                vec![
+                    Output::from(Event::Connected(id, direction)),
+                    Output::from(Internal::Upgrade(conn_id, id)),
+                ]
+            }
+        }
+    }
+
+    /// A peer told us it rejected one of our connections as a duplicate, which means it
+    /// already sees the opposite direction as connected too. Resolve the clash exactly like a
+    /// simultaneous dial, unless the rejected connection is the only one we know about, in
+    /// which case there's nothing to race against and we just drop it.
+    fn handle_duplicate_conn_rejected(
+        &mut self,
+        conn_id: ConnId,
+        id: node::Id,
+        report: Report,
+    ) -> Vec<Output> {
+        match self.connected.get(&id) {
+            Some((existing_conn, existing_dir)) if *existing_conn != conn_id => {
+                let existing = (*existing_conn, *existing_dir);
+                self.start_tiebreak(id, existing, (conn_id, existing_dir.opposite()))
+            }
+            _ => {
+                self.connected.remove(&id);
+                vec![Output::from(Event::Disconnected(id, report))]
+            }
+        }
+    }
+
+    /// Generate our nonce for the simultaneous-open tie-break and announce it to the peer over
+    /// the connection that just clashed with the one we already had.
+    fn start_tiebreak(
+        &mut self,
+        id: node::Id,
+        existing: (ConnId, Direction),
+        incoming: (ConnId, Direction),
+    ) -> Vec<Output> {
+        let mut local_nonce = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut local_nonce);
+
+        let announce_on = incoming.0;
+
+        self.tiebreaks.insert(
+            id,
+            TieBreak {
+                existing,
+                incoming,
+                local_nonce,
+            },
+        );
+
+        vec![Output::from(Internal::Tiebreak(announce_on, id, local_nonce))]
+    }
+
+    /// The peer's nonce for an in-flight tie-break has arrived; compare it against ours and
+    /// settle the clash.
+    fn handle_tiebreak(&mut self, conn_id: ConnId, id: node::Id, remote_nonce: Nonce) -> Vec<Output> {
+        let Some(tiebreak) = self.tiebreaks.get(&id) else {
+            return vec![];
+        };
+
+        // The nonce belongs to the connection the tie-break is actually waiting on; ignore
+        // anything arriving late on a connection that's already been superseded.
+        if conn_id != tiebreak.incoming.0 {
+            return vec![];
+        }
+
+        match tiebreak.local_nonce.cmp(&remote_nonce) {
+            Ordering::Greater => self.resolve_tiebreak(id, Direction::Outgoing),
+            Ordering::Less => self.resolve_tiebreak(id, Direction::Incoming),
+            Ordering::Equal => self.drop_tiebreak(id),
+        }
+    }
+
+    /// One side of the clash won the nonce comparison: it keeps the connection in the given
+    /// direction, the other one is stopped.
+    fn resolve_tiebreak(&mut self, id: node::Id, winner_direction: Direction) -> Vec<Output> {
+        let Some(tiebreak) = self.tiebreaks.remove(&id) else {
+            return vec![];
+        };
+
+        let (winner, loser) = if tiebreak.existing.1 == winner_direction {
+            (tiebreak.existing, tiebreak.incoming)
+        } else {
+            (tiebreak.incoming, tiebreak.existing)
+        };
+
+        self.connected.insert(id, winner);
 
         vec![
-            Output::from(Event::Connected(id, Direction::Outgoing)),
-            Output::from(Internal::Upgrade(id)),
+            Output::from(Event::RoleResolved(id, winner.1)),
+            Output::from(Internal::Stop(loser.0, id)),
+        ]
+    }
+
+    /// Nonces matched exactly: neither side can be trusted to pick a winner, so both
+    /// connections are torn down and the dial is left to be retried from scratch.
+    fn drop_tiebreak(&mut self, id: node::Id) -> Vec<Output> {
+        let Some(tiebreak) = self.tiebreaks.remove(&id) else {
+            return vec![];
+        };
+        self.connected.remove(&id);
+
+        vec![
+            Output::from(Internal::Stop(tiebreak.existing.0, id)),
+            Output::from(Internal::Stop(tiebreak.incoming.0, id)),
         ]
     }
 
@@ -69,8 +206,23 @@ impl Protocol {
         vec![Output::from(Event::Message(id, msg))]
     }
 
-    fn handle_stopped(&mut self, id: node::Id, report: Option<Report>) -> Vec<Output> {
-        self.upgraded.remove(&id);
+    fn handle_stopped(
+        &mut self,
+        conn_id: ConnId,
+        id: node::Id,
+        report: Option<Report>,
+    ) -> Vec<Output> {
+        if self.connected.get(&id).map(|(c, _)| *c) == Some(conn_id) {
+            self.connected.remove(&id);
+        }
+        if self.upgraded.get(&id) == Some(&conn_id) {
+            self.upgraded.remove(&id);
+        }
+        if let Some(tiebreak) = self.tiebreaks.get(&id) {
+            if tiebreak.existing.0 == conn_id || tiebreak.incoming.0 == conn_id {
+                self.tiebreaks.remove(&id);
+            }
+        }
         self.stopped.insert(id);
 
         vec![Output::from(Event::Disconnected(
@@ -79,15 +231,143 @@ impl Protocol {
         ))]
     }
 
-    fn handle_upgraded(&mut self, id: node::Id) -> Vec<Output> {
-        self.upgraded.insert(id);
+    fn handle_upgraded(&mut self, conn_id: ConnId, id: node::Id) -> Vec<Output> {
+        // A stopped or superseded connection can still have an upgrade in flight; ignore it if
+        // it's no longer the connection we're tracking for this peer.
+        if self.connected.get(&id).map(|(c, _)| *c) != Some(conn_id) {
+            return vec![];
+        }
+
+        self.upgraded.insert(id, conn_id);
 
         vec![Output::from(Event::Upgraded(id))]
     }
 
-    fn handle_upgrade_failed(&mut self, id: node::Id, err: Report) -> Vec<Output> {
+    fn handle_upgrade_failed(&mut self, conn_id: ConnId, id: node::Id, err: Report) -> Vec<Output> {
+        if self.connected.get(&id).map(|(c, _)| *c) != Some(conn_id) {
+            return vec![];
+        }
+
         self.connected.remove(&id);
 
         vec![Output::from(Event::UpgradeFailed(id, err))]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(byte: u8) -> node::Id {
+        node::Id::new([byte; node::Id::LENGTH])
+    }
+
+    fn nonce_from(outputs: &[Output]) -> [u8; 32] {
+        outputs
+            .iter()
+            .find_map(|output| match output {
+                Output::Internal(Internal::Tiebreak(_, _, nonce)) => Some(*nonce),
+                _ => None,
+            })
+            .expect("expected an Internal::Tiebreak output")
+    }
+
+    fn conn_stopped(outputs: &[Output], conn_id: ConnId) -> bool {
+        outputs.iter().any(|output| {
+            matches!(output, Output::Internal(Internal::Stop(stopped, _)) if *stopped == conn_id)
+        })
+    }
+
+    /// A second connection in the same direction as one we already have must be stopped itself,
+    /// not the connection we already established.
+    #[test]
+    fn same_direction_duplicate_stops_the_new_connection() {
+        let mut protocol = Protocol::default();
+        let id = peer(1);
+
+        protocol.handle_connected(ConnId(1), id);
+        let outputs = protocol.handle_connected(ConnId(2), id);
+
+        assert!(conn_stopped(&outputs, ConnId(2)));
+        assert!(!conn_stopped(&outputs, ConnId(1)));
+        assert_eq!(protocol.connected.get(&id), Some(&(ConnId(1), Direction::Outgoing)));
+    }
+
+    /// When the peer's nonce beats ours, our incoming connection should win and the existing one
+    /// should be stopped instead.
+    #[test]
+    fn tiebreak_keeps_the_winning_connection() {
+        let mut protocol = Protocol::default();
+        let id = peer(2);
+
+        protocol.handle_connected(ConnId(1), id);
+        let clash = protocol.handle_accepted(ConnId(2), id);
+        let local_nonce = nonce_from(&clash);
+
+        // `[0xff; 32]` is the largest possible nonce, so it's guaranteed to beat whatever we
+        // generated and make the incoming (Incoming) connection win the tie-break.
+        let remote_nonce = [0xff; 32];
+
+        let outputs = protocol.handle_tiebreak(ConnId(2), id, remote_nonce);
+
+        assert!(conn_stopped(&outputs, ConnId(1)));
+        assert!(!conn_stopped(&outputs, ConnId(2)));
+        assert_eq!(protocol.connected.get(&id), Some(&(ConnId(2), Direction::Incoming)));
+        assert!(outputs
+            .iter()
+            .any(|output| matches!(output, Output::Event(Event::RoleResolved(_, Direction::Incoming)))));
+    }
+
+    /// A tie on the nonce comparison must drop both connections and leave nothing connected.
+    #[test]
+    fn tiebreak_tie_drops_both_connections() {
+        let mut protocol = Protocol::default();
+        let id = peer(3);
+
+        protocol.handle_connected(ConnId(1), id);
+        let clash = protocol.handle_accepted(ConnId(2), id);
+        let local_nonce = nonce_from(&clash);
+
+        let outputs = protocol.handle_tiebreak(ConnId(2), id, local_nonce);
+
+        assert!(conn_stopped(&outputs, ConnId(1)));
+        assert!(conn_stopped(&outputs, ConnId(2)));
+        assert!(protocol.connected.get(&id).is_none());
+    }
+
+    /// The peer rejecting one of our connections as a duplicate while we still have another
+    /// live connection for the same peer should settle via a tie-break, not silently drop the
+    /// connection we still want.
+    #[test]
+    fn duplicate_conn_rejected_while_connected_starts_a_tiebreak() {
+        let mut protocol = Protocol::default();
+        let id = peer(4);
+
+        protocol.handle_connected(ConnId(1), id);
+        let outputs =
+            protocol.handle_duplicate_conn_rejected(ConnId(2), id, Report::msg("duplicate"));
+
+        assert!(outputs
+            .iter()
+            .any(|output| matches!(output, Output::Internal(Internal::Tiebreak(c, _, _)) if *c == ConnId(2))));
+        assert!(protocol.tiebreaks.contains_key(&id));
+        // The connection we already had is untouched until the tie-break resolves.
+        assert_eq!(protocol.connected.get(&id), Some(&(ConnId(1), Direction::Outgoing)));
+    }
+
+    /// A duplicate-rejected notice for a connection we have no other record of just disconnects
+    /// it; there's nothing to race against.
+    #[test]
+    fn duplicate_conn_rejected_without_another_connection_just_disconnects() {
+        let mut protocol = Protocol::default();
+        let id = peer(5);
+
+        let outputs =
+            protocol.handle_duplicate_conn_rejected(ConnId(1), id, Report::msg("duplicate"));
+
+        assert!(!protocol.tiebreaks.contains_key(&id));
+        assert!(outputs
+            .iter()
+            .any(|output| matches!(output, Output::Event(Event::Disconnected(_, _)))));
+    }
+}
@@ -2,24 +2,219 @@
 
 use signatory::{ed25519, Ed25519Seed};
 use signatory_dalek::Ed25519Signer;
+use std::collections::HashMap;
 use std::marker::{Send, Sync};
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 use types::{PingRequest, PingResponse, PubKeyMsg};
 
 use ed25519::keyring::KeyRing;
 use error::KmsError;
 use prost::Message;
-use rpc::{Request, Response, TendermintResponse};
+use rpc::{
+    Request, Response, SignHeartbeatRequest, SignProposalRequest, SignVoteRequest,
+    TendermintResponse,
+};
 use tm_secret_connection::SecretConnection;
 use unix_connection::UNIXConnection;
 
+fn io_err<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Maps each `chain_id` this session is willing to sign for to the identity of the key in the
+/// `KeyRing` that should be used for it.
+///
+/// This is what stands between a compromised or misconfigured validator and equivocating across
+/// chains that happen to share a KMS: a request for a chain we have no entry for is rejected
+/// rather than silently signed with "the only key".
+#[derive(Clone, Default)]
+pub struct ChainKeyConfig {
+    keys: HashMap<String, ed25519::PublicKey>,
+}
+
+impl ChainKeyConfig {
+    /// Build a config mapping each given `chain_id` to the identity of the key that signs for
+    /// it.
+    pub fn new(keys: HashMap<String, ed25519::PublicKey>) -> Self {
+        Self { keys }
+    }
+
+    fn key_for(&self, chain_id: &str) -> Result<&ed25519::PublicKey, KmsError> {
+        if !is_valid_chain_id(chain_id) {
+            return Err(KmsError::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid chain_id '{}'", chain_id),
+            )));
+        }
+
+        self.keys.get(chain_id).ok_or_else(|| {
+            KmsError::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no signing key configured for chain '{}'", chain_id),
+            ))
+        })
+    }
+}
+
+/// Tendermint chain IDs are conventionally short alphanumeric (plus `-`/`_`) identifiers. We
+/// enforce that shape strictly here because `chain_id` comes straight off the wire and is later
+/// used to build a file path for the double-sign guard; anything else (path separators, `..`,
+/// NUL, …) is rejected before it ever reaches the filesystem.
+fn is_valid_chain_id(chain_id: &str) -> bool {
+    !chain_id.is_empty()
+        && chain_id.len() <= 50
+        && chain_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The `(height, round, step)` of the highest proposal or vote signed so far for a chain.
+///
+/// Tendermint's consensus step ordering (`propose < prevote < precommit`) means a request is
+/// only safe to sign if it is strictly greater than the last one signed; anything else is either
+/// a replay or an attempt to get the KMS to equivocate.
+type ConsensusState = (i64, i64, i8);
+
+/// The consensus-position and chain-routing fields `sign` needs but which `rpc::TendermintResponse`
+/// doesn't expose, since that trait only cares about producing and attaching a signature.
+///
+/// These are implemented directly against the `chain_id`/`height`/`round` fields already present
+/// on each wire request (per the Tendermint privval proto), rather than added to
+/// `TendermintResponse` itself, to avoid widening that trait's contract for every other
+/// implementor that doesn't need double-sign protection.
+trait ChainAware {
+    fn chain_id(&self) -> &str;
+    fn consensus_state(&self) -> ConsensusState;
+}
+
+/// Proposals don't have a vote type; fix their step to 0 so they still sort below any prevote or
+/// precommit at the same `(height, round)`.
+const STEP_PROPOSE: i8 = 0;
+
+impl ChainAware for SignProposalRequest {
+    fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    fn consensus_state(&self) -> ConsensusState {
+        (self.proposal.height, self.proposal.round, STEP_PROPOSE)
+    }
+}
+
+impl ChainAware for SignVoteRequest {
+    fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    fn consensus_state(&self) -> ConsensusState {
+        (self.vote.height, self.vote.round, self.vote.vote_type as i8)
+    }
+}
+
+impl ChainAware for SignHeartbeatRequest {
+    fn chain_id(&self) -> &str {
+        &self.chain_id
+    }
+
+    fn consensus_state(&self) -> ConsensusState {
+        (self.heartbeat.height, self.heartbeat.round, STEP_PROPOSE)
+    }
+}
+
+/// Per-chain high-water marks, persisted to disk so a restart can't be used to reset them and
+/// trick the signer into equivocating.
+struct DoubleSignGuard {
+    dir: PathBuf,
+    marks: HashMap<String, ConsensusState>,
+}
+
+impl DoubleSignGuard {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            marks: HashMap::new(),
+        }
+    }
+
+    fn mark_path(&self, chain_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.hwm", chain_id))
+    }
+
+    fn high_water_mark(&mut self, chain_id: &str) -> Result<Option<ConsensusState>, KmsError> {
+        if let Some(mark) = self.marks.get(chain_id) {
+            return Ok(Some(*mark));
+        }
+
+        match fs::read_to_string(self.mark_path(chain_id)) {
+            Ok(contents) => {
+                let mark = Self::parse(&contents)?;
+                self.marks.insert(chain_id.to_owned(), mark);
+                Ok(Some(mark))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(KmsError::from(e)),
+        }
+    }
+
+    fn parse(contents: &str) -> Result<ConsensusState, KmsError> {
+        let invalid = || {
+            KmsError::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed high-water mark file",
+            ))
+        };
+        let mut parts = contents.trim().split(' ');
+        let height = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        let round = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        let step = parts.next().ok_or_else(invalid)?.parse().map_err(|_| invalid())?;
+        Ok((height, round, step))
+    }
+
+    /// Reject anything that isn't strictly newer than the last signed `(height, round, step)`.
+    ///
+    /// This only validates; it doesn't persist. Call [`Self::commit`] with the same `mark` once
+    /// the request has actually been signed — advancing the mark before that point would
+    /// permanently burn the slot even if signing then failed (HSM hiccup, encode error, ...),
+    /// turning a transient failure into a missed vote forever.
+    fn check(&mut self, chain_id: &str, mark: ConsensusState) -> Result<(), KmsError> {
+        if let Some(last) = self.high_water_mark(chain_id)? {
+            if mark <= last {
+                return Err(KmsError::from(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "refusing to sign ({:?}) for chain '{}': not strictly greater than the \
+                         last signed ({:?})",
+                        mark, chain_id, last
+                    ),
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist `mark` as the new high-water mark for `chain_id`, once it has actually been
+    /// signed.
+    fn commit(&mut self, chain_id: &str, mark: ConsensusState) -> Result<(), KmsError> {
+        fs::write(
+            self.mark_path(chain_id),
+            format!("{} {} {}", mark.0, mark.1, mark.2),
+        )?;
+        self.marks.insert(chain_id.to_owned(), mark);
+        Ok(())
+    }
+}
+
 /// Encrypted session with a validator node
 pub struct Session<Connection> {
     /// TCP connection to a validator node
     connection: Connection,
+    /// Which key in the `KeyRing` to use for each chain this session may sign for
+    chain_keys: ChainKeyConfig,
+    /// Last signed `(height, round, step)` per chain, to prevent double-signing
+    double_sign_guard: DoubleSignGuard,
 }
 
 impl Session<SecretConnection<TcpStream>> {
@@ -28,18 +223,28 @@ impl Session<SecretConnection<TcpStream>> {
         addr: &str,
         port: u16,
         secret_connection_key: &Ed25519Seed,
+        chain_keys: ChainKeyConfig,
+        state_dir: &Path,
     ) -> Result<Self, KmsError> {
         debug!("Connecting to {}:{}...", addr, port);
         let socket = TcpStream::connect(format!("{}:{}", addr, port))?;
         let signer = Ed25519Signer::from(secret_connection_key);
         let public_key = ed25519::public_key(&signer)?;
         let connection = SecretConnection::new(socket, &public_key, &signer)?;
-        Ok(Self { connection })
+        Ok(Self {
+            connection,
+            chain_keys,
+            double_sign_guard: DoubleSignGuard::new(state_dir.to_path_buf()),
+        })
     }
 }
 
 impl Session<UNIXConnection<UnixStream>> {
-    pub fn new_unix(socket_path: &PathBuf) -> Result<Self, KmsError> {
+    pub fn new_unix(
+        socket_path: &PathBuf,
+        chain_keys: ChainKeyConfig,
+        state_dir: &Path,
+    ) -> Result<Self, KmsError> {
         // Try to unlink the socket path, shouldn't fail if it doesn't exist
         if let Err(e) = fs::remove_file(socket_path) {
             if e.kind() != io::ErrorKind::NotFound {
@@ -59,7 +264,85 @@ impl Session<UNIXConnection<UnixStream>> {
         debug!("Stopped listening on {}", socket_path.to_str().unwrap());
 
         let connection = UNIXConnection::new(socket)?;
-        Ok(Self { connection })
+        Ok(Self {
+            connection,
+            chain_keys,
+            double_sign_guard: DoubleSignGuard::new(state_dir.to_path_buf()),
+        })
+    }
+}
+
+/// A bidirectional QUIC stream, adapted to the blocking `io::Read + io::Write` interface the
+/// rest of `Session` is built on so the request/response framing in [`Session::handle_request`]
+/// doesn't need to change.
+pub struct QuicConnection {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+}
+
+impl QuicConnection {
+    fn new(send: quinn::SendStream, recv: quinn::RecvStream) -> Self {
+        Self { send, recv }
+    }
+}
+
+impl io::Read for QuicConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        futures::executor::block_on(self.recv.read(buf))
+            .map_err(io_err)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "QUIC stream finished"))
+    }
+}
+
+impl io::Write for QuicConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        futures::executor::block_on(self.send.write(buf)).map_err(io_err)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Session<QuicConnection> {
+    /// Create a new session with the validator at the given address over QUIC.
+    ///
+    /// Opens one QUIC connection with a full handshake and carries requests over a single
+    /// bidirectional stream, reusing the same `handle_request`/`sign` logic as the
+    /// `SecretConnection`/TCP and UNIX socket transports. 0-RTT is not attempted, and a lost
+    /// connection is not re-established by this session.
+    pub fn new_quic(
+        addr: &str,
+        port: u16,
+        client_config: quinn::ClientConfig,
+        chain_keys: ChainKeyConfig,
+        state_dir: &Path,
+    ) -> Result<Self, KmsError> {
+        debug!("Connecting to {}:{} over QUIC...", addr, port);
+
+        let mut endpoint =
+            quinn::Endpoint::client("0.0.0.0:0".parse().unwrap()).map_err(io_err)?;
+        endpoint.set_default_client_config(client_config);
+
+        // `SocketAddr::parse` only accepts literal IPs; resolve `addr` the same way
+        // `TcpStream::connect` does for the `SecretConnection` transport so hostnames work here too.
+        let remote = (addr, port)
+            .to_socket_addrs()
+            .map_err(io_err)?
+            .next()
+            .ok_or_else(|| io_err(format!("could not resolve '{}:{}'", addr, port)))?;
+
+        let (send, recv) = futures::executor::block_on(async {
+            let connection = endpoint.connect(remote, addr).map_err(io_err)?.await.map_err(io_err)?;
+            debug!("QUIC handshake complete with {}", connection.remote_address());
+            connection.open_bi().await.map_err(io_err)
+        })?;
+
+        Ok(Self {
+            connection: QuicConnection::new(send, recv),
+            chain_keys,
+            double_sign_guard: DoubleSignGuard::new(state_dir.to_path_buf()),
+        })
     }
 }
 
@@ -89,14 +372,29 @@ impl<Connection: io::Read + io::Write + Sync + Send> Session<Connection> {
     }
 
     /// Perform a digital signature operation
-    fn sign(&mut self, mut request: impl TendermintResponse) -> Result<Response, KmsError> {
+    fn sign(
+        &mut self,
+        mut request: impl TendermintResponse + ChainAware,
+    ) -> Result<Response, KmsError> {
+        let chain_id = request.chain_id().to_owned();
+
+        // Validate (and resolve the key for) `chain_id` *before* the double-sign guard ever
+        // touches disk: `chain_id` comes straight off the wire, and advancing the high-water
+        // mark for an unconfigured or malformed chain would both write to an attacker-chosen
+        // path and permanently poison that mark once the chain is configured for real.
+        let key_id = self.chain_keys.key_for(&chain_id)?;
+
+        let mark = request.consensus_state();
+        self.double_sign_guard.check(&chain_id, mark)?;
+
         let mut to_sign = vec![];
-        // TODO(ismail): this should either be a config param, or, included in the request!
-        let chain_id = "test_chain_id";
-        request.sign_bytes(chain_id, &mut to_sign)?;
-        // TODO(ismail): figure out which key to use here instead of taking the only key
-        // from keyring here:
-        let sig = KeyRing::sign(None, &to_sign)?;
+        request.sign_bytes(&chain_id, &mut to_sign)?;
+
+        let sig = KeyRing::sign(Some(key_id), &to_sign)?;
+
+        // Only burn the high-water mark once a signature has actually been produced, so a
+        // transient signing failure can be retried instead of permanently blocking the slot.
+        self.double_sign_guard.commit(&chain_id, mark)?;
 
         request.set_signature(&sig);
         Ok(request.build_response())